@@ -1,9 +1,36 @@
 //! Consistent definitions for realize error types.
-use failure;
+use std::fmt;
+use std::path;
 use std::result;
 
+use failure;
+
 /// A result whose error is `Error`.
 pub type Result<A> = result::Result<A, Error>;
 
 /// An error originating from a realize operation.
 pub type Error = failure::Error;
+
+/// Indicates that an advisory lock could not be acquired because another
+/// process already holds it. Distinct from other errors so that callers can
+/// tell a busy lock apart from a genuine realization failure.
+#[derive(Debug)]
+pub struct LockBusy {
+    /// The path of the lock file that is already held.
+    pub lock_path: path::PathBuf,
+    /// Whatever identifying information the current holder recorded in the
+    /// lock file (e.g. its PID), if it could be read.
+    pub holder: String,
+}
+
+impl fmt::Display for LockBusy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Lock {:?} is already held by another process ({})",
+            self.lock_path, self.holder
+        )
+    }
+}
+
+impl failure::Fail for LockBusy {}
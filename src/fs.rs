@@ -1,13 +1,14 @@
 //! File-system related resource types.
 use std::any;
+use std::collections;
 use std::fmt;
-use std::fs;
 use std::io;
-use std::os;
 use std::path;
 
 use error;
+use ini;
 use resource;
+use state_cache;
 use util;
 
 /// A file, which can be absent, a regular file, a directory, or a symlink.
@@ -97,60 +98,111 @@ impl resource::Resource for File {
         resource::Key::Path(self.path.clone())
     }
 
-    fn realize(&self, &resource::Context { log, .. }: &resource::Context) -> error::Result<()> {
-        use error::ResultExt;
+    fn realize(
+        &self,
+        &resource::Context {
+            log,
+            vfs,
+            state_cache,
+        }: &resource::Context,
+    ) -> error::Result<()> {
+        use failure::ResultExt;
         let path = self.path.to_string_lossy().into_owned();
         let log = log.new(o!("path" => path));
 
         match self.file_type {
             FileType::Absent => {
-                if self.path.is_dir() {
-                    trace!(log, "Deleting directory");
-                    fs::remove_dir(&self.path)
-                        .chain_err(|| format!("Failed to delete directory {:?}", self.path))?;
+                match vfs.metadata(&self.path).ok() {
+                    Some(ref metadata) if metadata.is_dir() => {
+                        trace!(log, "Deleting directory");
+                        vfs.remove_dir(&self.path)
+                            .with_context(|_| format!("Failed to delete directory {:?}", self.path))?;
+                    }
+                    Some(ref metadata) if metadata.is_file() => {
+                        trace!(log, "Deleting file");
+                        vfs.remove_file(&self.path)
+                            .with_context(|_| format!("Failed to delete file {:?}", self.path))?;
+                    }
+                    _ => {}
                 }
-                if self.path.is_file() {
-                    trace!(log, "Deleting file");
-                    fs::remove_file(&self.path)
-                        .chain_err(|| format!("Failed to delete file {:?}", self.path))?;
+                if let Some(state_cache) = state_cache {
+                    state_cache.remove(&self.key()).with_context(|_| {
+                        format!("Failed to update state cache for path {:?}", self.path)
+                    })?;
                 }
             }
             FileType::File { ref contents } => {
                 if let Some(ref contents) = *contents {
-                    use std::io::Write;
-
                     trace!(log, "Updating file contents");
-                    let mut f = fs::File::create(&self.path)
-                        .chain_err(|| format!("Failed to create file {:?}", self.path))?;
-                    f.write_all(contents)
-                        .chain_err(|| format!("Failed to write to file {:?}", self.path))?;
+                    vfs.create(&self.path, contents)
+                        .with_context(|_| format!("Failed to create file {:?}", self.path))?;
+
+                    if let Some(state_cache) = state_cache {
+                        let metadata = vfs.metadata(&self.path).with_context(|_| {
+                            format!("Failed to gather metadata about path {:?}", self.path)
+                        })?;
+                        let mtime = metadata.modified().with_context(|_| {
+                            format!("Failed to read mtime of path {:?}", self.path)
+                        })?;
+                        let sha1 = util::sha1(io::Cursor::new(contents))
+                            .with_context(|_| "Failed to compute SHA-1 digest")?
+                            .to_string();
+                        state_cache
+                            .put(
+                                &self.key(),
+                                state_cache::Entry {
+                                    path: self.path.clone(),
+                                    size: metadata.len(),
+                                    mtime: mtime,
+                                    sha1: sha1,
+                                },
+                            )
+                            .with_context(|_| {
+                                format!("Failed to update state cache for path {:?}", self.path)
+                            })?;
+                    }
                 }
             }
             FileType::Dir => {
-                fs::create_dir_all(&self.path)
-                    .chain_err(|| format!("Failed to create directory {:?}", self.path))?;
+                vfs.create_dir_all(&self.path)
+                    .with_context(|_| format!("Failed to create directory {:?}", self.path))?;
             }
             FileType::Symlink { ref target } => {
-                // TODO: add support for other OSes
-                os::unix::fs::symlink(target, &self.path)
-                    .chain_err(|| format!("Failed to create symlink {:?}", self.path))?;
+                vfs.symlink(target, &self.path)
+                    .with_context(|_| format!("Failed to create symlink {:?}", self.path))?;
             }
         }
         Ok(())
     }
 
-    fn verify(&self, &resource::Context { log, .. }: &resource::Context) -> error::Result<bool> {
-        use error::ResultExt;
+    fn verify(
+        &self,
+        &resource::Context {
+            log,
+            vfs,
+            state_cache,
+        }: &resource::Context,
+    ) -> error::Result<bool> {
+        use failure::ResultExt;
 
         let log = log.new(o!("path" => self.path.to_string_lossy().into_owned()));
 
-        if !self.path.exists() {
-            debug!(log, "Path does not exist");
-            return Ok(false);
-        }
-
-        let metadata = fs::metadata(&self.path)
-            .chain_err(|| format!("Failed to gather metadata about path {:?}", self.path))?;
+        // Symlinks must be looked up without following the final component,
+        // or `is_symlink()` could never be true.
+        let metadata_result = match self.file_type {
+            FileType::Symlink { .. } => vfs.symlink_metadata(&self.path),
+            _ => vfs.metadata(&self.path),
+        };
+        let metadata = match metadata_result {
+            Ok(metadata) => metadata,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!(log, "Path does not exist");
+                return Ok(false);
+            }
+            Err(e) => Err(e).with_context(|_| {
+                format!("Failed to gather metadata about path {:?}", self.path)
+            })?,
+        };
         match self.file_type {
             FileType::File { ref contents } => {
                 if !metadata.file_type().is_file() {
@@ -159,15 +211,35 @@ impl resource::Resource for File {
                 }
 
                 if let Some(ref contents) = *contents {
-                    let file = fs::File::open(&self.path)
-                        .chain_err(|| format!("Failed to open file {:?} for hashing", self.path))?;
-                    let old_sha1 = util::sha1(file)
-                        .chain_err(|| {
-                            format!("Failed to compute SHA-1 digest of file {:?}", self.path)
-                        })?
-                        .to_string();
+                    let cached = state_cache.and_then(|state_cache| state_cache.get(&self.key()));
+                    let up_to_date_sha1 = cached.as_ref().and_then(|cached| {
+                        if cached.size == metadata.len()
+                            && metadata.modified().ok().map_or(false, |m| m == cached.mtime)
+                        {
+                            Some(cached.sha1.clone())
+                        } else {
+                            None
+                        }
+                    });
+
+                    let old_sha1 = match up_to_date_sha1 {
+                        Some(sha1) => sha1,
+                        None => {
+                            let file = vfs.open(&self.path).with_context(|_| {
+                                format!("Failed to open file {:?} for hashing", self.path)
+                            })?;
+                            util::sha1(file)
+                                .with_context(|_| {
+                                    format!(
+                                        "Failed to compute SHA-1 digest of file {:?}",
+                                        self.path
+                                    )
+                                })?
+                                .to_string()
+                        }
+                    };
                     let new_sha1 = util::sha1(io::Cursor::new(contents))
-                        .chain_err(|| "Failed to compute SHA-1 digest")?
+                        .with_context(|_| "Failed to compute SHA-1 digest")?
                         .to_string();
                     if old_sha1 != new_sha1 {
                         debug!(log, "File has wrong contents";
@@ -190,8 +262,8 @@ impl resource::Resource for File {
                     return Ok(false);
                 }
 
-                let old_target = fs::read_link(&self.path)
-                    .chain_err(|| format!("Failed to read link target of {:?}", self.path))?;
+                let old_target = vfs.read_link(&self.path)
+                    .with_context(|_| format!("Failed to read link target of {:?}", self.path))?;
                 if old_target != *new_target {
                     let old_target = old_target.to_string_lossy().into_owned();
                     let new_target = new_target.to_string_lossy().into_owned();
@@ -247,3 +319,352 @@ impl fmt::Display for File {
         Ok(())
     }
 }
+
+/// A single `key = value` entry within a section of an INI-style config
+/// file, managed independently of the rest of the file's content. Unlike
+/// `File::contains`, this lets several resources cooperatively manage
+/// different entries of a shared config file (e.g. under `/etc`), without
+/// each one owning the whole file.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ConfigFile {
+    path: path::PathBuf,
+    section: String,
+    key: String,
+    value: ConfigValue,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum ConfigValue {
+    Set(String),
+    Unset,
+}
+
+impl ConfigFile {
+    /// Starts managing the `key` entry within `section` of the INI-style
+    /// config file at `path`. By default the entry is expected to be
+    /// absent; call `is` to require a specific value instead.
+    pub fn at<P, S, K>(path: P, section: S, key: K) -> ConfigFile
+    where
+        P: Into<path::PathBuf>,
+        S: Into<String>,
+        K: Into<String>,
+    {
+        ConfigFile {
+            path: path.into(),
+            section: section.into(),
+            key: key.into(),
+            value: ConfigValue::Unset,
+        }
+    }
+
+    /// The entry should resolve to the specified value.
+    pub fn is<V>(mut self, value: V) -> ConfigFile
+    where
+        V: Into<String>,
+    {
+        self.value = ConfigValue::Set(value.into());
+        self
+    }
+
+    /// The entry should be absent, and will be removed if present.
+    pub fn is_unset(mut self) -> ConfigFile {
+        self.value = ConfigValue::Unset;
+        self
+    }
+}
+
+impl resource::Resource for ConfigFile {
+    fn key(&self) -> resource::Key {
+        let mut map = collections::BTreeMap::new();
+        map.insert("path".to_string(), resource::Key::Path(self.path.clone()));
+        map.insert(
+            "section".to_string(),
+            resource::Key::String(self.section.clone()),
+        );
+        map.insert("key".to_string(), resource::Key::String(self.key.clone()));
+        resource::Key::Map(map)
+    }
+
+    fn realize(
+        &self,
+        &resource::Context { log, vfs, .. }: &resource::Context,
+    ) -> error::Result<()> {
+        use failure::ResultExt;
+
+        let log = log.new(o!("path" => self.path.to_string_lossy().into_owned(),
+                              "section" => self.section.clone(), "key" => self.key.clone()));
+
+        let mut document = ini::Document::read(vfs, &self.path)
+            .with_context(|_| format!("Failed to read config file {:?}", self.path))?;
+
+        match self.value {
+            ConfigValue::Set(ref value) => {
+                trace!(log, "Setting config entry");
+                document.set(&self.section, &self.key, value);
+            }
+            ConfigValue::Unset => {
+                trace!(log, "Removing config entry");
+                document.unset(&self.section, &self.key);
+            }
+        }
+
+        vfs.create(&self.path, document.render().as_bytes())
+            .with_context(|_| format!("Failed to write config file {:?}", self.path))?;
+
+        Ok(())
+    }
+
+    fn verify(
+        &self,
+        &resource::Context { log, vfs, .. }: &resource::Context,
+    ) -> error::Result<bool> {
+        use failure::ResultExt;
+
+        let log = log.new(o!("path" => self.path.to_string_lossy().into_owned(),
+                              "section" => self.section.clone(), "key" => self.key.clone()));
+
+        let document = ini::Document::read(vfs, &self.path)
+            .with_context(|_| format!("Failed to read config file {:?}", self.path))?;
+
+        let current = document.get(&self.section, &self.key);
+
+        match (current, &self.value) {
+            (None, &ConfigValue::Unset) => {
+                trace!(log, "Config entry is up to date");
+                Ok(true)
+            }
+            (Some(ref current), &ConfigValue::Set(ref value)) if current == value => {
+                trace!(log, "Config entry is up to date");
+                Ok(true)
+            }
+            (None, &ConfigValue::Set(..)) => {
+                debug!(log, "Config entry is absent");
+                Ok(false)
+            }
+            (Some(ref current), &ConfigValue::Set(ref value)) => {
+                debug!(log, "Config entry has the wrong value";
+                       "old_value" => current.clone(), "new_value" => value.clone());
+                Ok(false)
+            }
+            (Some(ref current), &ConfigValue::Unset) => {
+                debug!(log, "Config entry should be absent"; "old_value" => current.clone());
+                Ok(false)
+            }
+        }
+    }
+
+    fn as_any(&self) -> &any::Any {
+        self
+    }
+}
+
+impl resource::UnresolvedResource for ConfigFile {
+    fn implicit_ensure<E>(&self, ensurer: &mut E)
+    where
+        E: resource::Ensurer,
+    {
+        if let Some(parent) = self.path.parent() {
+            ensurer.ensure(File::at(parent).is_dir());
+        }
+    }
+}
+
+impl fmt::Display for ConfigFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "config entry {}.{} in {:?}", self.section, self.key, self.path)?;
+        match self.value {
+            ConfigValue::Set(ref value) => write!(f, " set to {:?}", value)?,
+            ConfigValue::Unset => write!(f, " unset")?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path;
+    use std::process;
+    use std::sync::atomic;
+
+    use slog;
+
+    use resource;
+    use resource::Resource;
+    use state_cache;
+    use util;
+    use vfs;
+    use vfs::Vfs;
+
+    use super::{ConfigFile, File};
+
+    /// A `Vfs` that delegates to the real file system but counts how many
+    /// times `open` is called, so that tests can assert whether `verify`
+    /// actually had to read and re-hash a file's contents.
+    #[derive(Debug)]
+    struct CountingVfs {
+        inner: vfs::RealVfs,
+        open_count: RefCell<usize>,
+    }
+
+    impl CountingVfs {
+        fn new() -> CountingVfs {
+            CountingVfs {
+                inner: vfs::RealVfs,
+                open_count: RefCell::new(0),
+            }
+        }
+    }
+
+    impl Vfs for CountingVfs {
+        fn metadata(&self, path: &path::Path) -> io::Result<fs::Metadata> {
+            self.inner.metadata(path)
+        }
+
+        fn symlink_metadata(&self, path: &path::Path) -> io::Result<fs::Metadata> {
+            self.inner.symlink_metadata(path)
+        }
+
+        fn read_link(&self, path: &path::Path) -> io::Result<path::PathBuf> {
+            self.inner.read_link(path)
+        }
+
+        fn open(&self, path: &path::Path) -> io::Result<Box<io::Read>> {
+            *self.open_count.borrow_mut() += 1;
+            self.inner.open(path)
+        }
+
+        fn create(&self, path: &path::Path, contents: &[u8]) -> io::Result<()> {
+            self.inner.create(path, contents)
+        }
+
+        fn remove_file(&self, path: &path::Path) -> io::Result<()> {
+            self.inner.remove_file(path)
+        }
+
+        fn remove_dir(&self, path: &path::Path) -> io::Result<()> {
+            self.inner.remove_dir(path)
+        }
+
+        fn create_dir_all(&self, path: &path::Path) -> io::Result<()> {
+            self.inner.create_dir_all(path)
+        }
+
+        fn symlink(&self, target: &path::Path, path: &path::Path) -> io::Result<()> {
+            self.inner.symlink(target, path)
+        }
+    }
+
+    fn unique_path(name: &str) -> path::PathBuf {
+        static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
+        env::temp_dir().join(format!("realize-fs-test-{}-{}-{}", process::id(), n, name))
+    }
+
+    #[test]
+    fn verify_skips_rehash_when_cache_matches() {
+        let path = unique_path("cache-hit");
+        let cache_path = unique_path("cache-hit.cache");
+        let contents = b"hello world".to_vec();
+        fs::write(&path, &contents).unwrap();
+
+        let metadata = fs::metadata(&path).unwrap();
+        let sha1 = util::sha1(io::Cursor::new(&contents)).unwrap().to_string();
+
+        let state_cache = state_cache::StateCache::open(&cache_path).unwrap();
+        let file = File::at(&path).contains(contents.clone());
+        state_cache
+            .put(
+                &file.key(),
+                state_cache::Entry {
+                    path: path.clone(),
+                    size: metadata.len(),
+                    mtime: metadata.modified().unwrap(),
+                    sha1: sha1,
+                },
+            )
+            .unwrap();
+
+        let log = slog::Logger::root(slog::Discard, o!());
+        let vfs = CountingVfs::new();
+        let ctx = resource::Context {
+            log: &log,
+            vfs: &vfs,
+            state_cache: Some(&state_cache),
+        };
+
+        assert_eq!(file.verify(&ctx).unwrap(), true);
+        assert_eq!(*vfs.open_count.borrow(), 0);
+
+        fs::remove_file(&path).ok();
+        fs::remove_file(&cache_path).ok();
+    }
+
+    #[test]
+    fn verify_rehashes_when_cached_size_differs() {
+        let path = unique_path("size-mismatch");
+        let cache_path = unique_path("size-mismatch.cache");
+        let contents = b"hello world".to_vec();
+        fs::write(&path, &contents).unwrap();
+
+        let metadata = fs::metadata(&path).unwrap();
+
+        let state_cache = state_cache::StateCache::open(&cache_path).unwrap();
+        let file = File::at(&path).contains(contents.clone());
+        state_cache
+            .put(
+                &file.key(),
+                state_cache::Entry {
+                    path: path.clone(),
+                    size: metadata.len() + 1,
+                    mtime: metadata.modified().unwrap(),
+                    sha1: "stale".to_string(),
+                },
+            )
+            .unwrap();
+
+        let log = slog::Logger::root(slog::Discard, o!());
+        let vfs = CountingVfs::new();
+        let ctx = resource::Context {
+            log: &log,
+            vfs: &vfs,
+            state_cache: Some(&state_cache),
+        };
+
+        assert_eq!(file.verify(&ctx).unwrap(), true);
+        assert_eq!(*vfs.open_count.borrow(), 1);
+
+        fs::remove_file(&path).ok();
+        fs::remove_file(&cache_path).ok();
+    }
+
+    #[test]
+    fn config_file_realize_verify_round_trip() {
+        let path = unique_path("config-file");
+
+        let log = slog::Logger::root(slog::Discard, o!());
+        let vfs = vfs::RealVfs;
+        let ctx = resource::Context {
+            log: &log,
+            vfs: &vfs,
+            state_cache: None,
+        };
+
+        let entry = ConfigFile::at(&path, "section", "key").is("value");
+
+        assert_eq!(entry.verify(&ctx).unwrap(), false);
+        entry.realize(&ctx).unwrap();
+        assert_eq!(entry.verify(&ctx).unwrap(), true);
+
+        let unset = ConfigFile::at(&path, "section", "key").is_unset();
+
+        assert_eq!(unset.verify(&ctx).unwrap(), false);
+        unset.realize(&ctx).unwrap();
+        assert_eq!(unset.verify(&ctx).unwrap(), true);
+
+        fs::remove_file(&path).ok();
+    }
+}
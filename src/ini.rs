@@ -0,0 +1,259 @@
+//! A small line-oriented parser and renderer for INI-style config files,
+//! used by `fs::ConfigFile` to manage individual `section.key = value`
+//! entries while leaving the rest of a file's content — comments, blank
+//! lines, and unrelated entries — byte-identical.
+use std::io;
+use std::io::Read;
+use std::path;
+
+use regex;
+
+use error;
+use vfs;
+
+#[derive(Debug)]
+struct Patterns {
+    section: regex::Regex,
+    item: regex::Regex,
+    continuation: regex::Regex,
+    comment_or_blank: regex::Regex,
+}
+
+impl Patterns {
+    fn new() -> Patterns {
+        Patterns {
+            section: regex::Regex::new(r"^\[([^\[]+)\]").unwrap(),
+            item: regex::Regex::new(r"^([^=\s][^=]*?)\s*=\s*((.*\S)?)").unwrap(),
+            continuation: regex::Regex::new(r"^[ \t]+\S").unwrap(),
+            comment_or_blank: regex::Regex::new(r"^(;|#|\s*$)").unwrap(),
+        }
+    }
+}
+
+/// A parsed INI-style document, retaining its original lines so that it can
+/// be rewritten with minimal, surgical changes.
+#[derive(Debug)]
+pub struct Document {
+    lines: Vec<String>,
+    trailing_newline: bool,
+    patterns: Patterns,
+}
+
+impl Document {
+    /// Reads the document at `path` through `vfs`, treating a missing file
+    /// as an empty document.
+    pub fn read(vfs: &vfs::Vfs, path: &path::Path) -> error::Result<Document> {
+        let contents = match vfs.open(path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                contents
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Document::parse(&contents))
+    }
+
+    fn parse(contents: &str) -> Document {
+        // Split on `\n` only, so that a `\r` of a `\r\n` line ending stays
+        // part of its line and is rendered back unchanged.
+        let trailing_newline = contents.is_empty() || contents.ends_with('\n');
+        let mut lines: Vec<String> = contents.split('\n').map(|line| line.to_string()).collect();
+        if trailing_newline {
+            lines.pop();
+        }
+        Document {
+            lines: lines,
+            trailing_newline: trailing_newline,
+            patterns: Patterns::new(),
+        }
+    }
+
+    /// Renders the document back to its textual form.
+    pub fn render(&self) -> String {
+        let mut rendered = self.lines.join("\n");
+        if self.trailing_newline && !self.lines.is_empty() {
+            rendered.push('\n');
+        }
+        rendered
+    }
+
+    /// Returns the current value of `section.key`, if the entry is present.
+    /// Continuation lines are joined with `\n`.
+    pub fn get(&self, section: &str, key: &str) -> Option<String> {
+        self.find(section, key)
+            .map(|(start, end)| self.value_of(start, end))
+    }
+
+    /// Ensures that `section.key` resolves to `value`, rewriting only the
+    /// affected lines (appending the section and/or key if either is
+    /// absent).
+    pub fn set(&mut self, section: &str, key: &str, value: &str) {
+        match self.find(section, key) {
+            Some((start, end)) => {
+                self.lines
+                    .splice(start..=end, vec![format!("{} = {}", key, value)]);
+            }
+            None => {
+                let at = self.section_end(section);
+                let mut insertion = Vec::new();
+                if self.section_start(section).is_none() {
+                    if !self.lines.is_empty() {
+                        insertion.push(String::new());
+                    }
+                    insertion.push(format!("[{}]", section));
+                }
+                insertion.push(format!("{} = {}", key, value));
+                self.lines.splice(at..at, insertion);
+            }
+        }
+    }
+
+    /// Ensures that `section.key` is absent, removing it if present.
+    pub fn unset(&mut self, section: &str, key: &str) {
+        if let Some((start, end)) = self.find(section, key) {
+            self.lines.drain(start..=end);
+        }
+    }
+
+    fn find(&self, section: &str, key: &str) -> Option<(usize, usize)> {
+        let patterns = &self.patterns;
+        let mut current_section = String::new();
+        let mut i = 0;
+        while i < self.lines.len() {
+            let line = &self.lines[i];
+
+            if let Some(caps) = patterns.section.captures(line) {
+                current_section = caps[1].to_string();
+                i += 1;
+                continue;
+            }
+
+            if patterns.comment_or_blank.is_match(line) {
+                i += 1;
+                continue;
+            }
+
+            if let Some(caps) = patterns.item.captures(line) {
+                let item_key = caps[1].trim().to_string();
+                let mut end = i;
+                while end + 1 < self.lines.len()
+                    && patterns.continuation.is_match(&self.lines[end + 1])
+                {
+                    end += 1;
+                }
+
+                if current_section == section && item_key == key {
+                    return Some((i, end));
+                }
+
+                i = end + 1;
+                continue;
+            }
+
+            i += 1;
+        }
+        None
+    }
+
+    fn value_of(&self, start: usize, end: usize) -> String {
+        let patterns = &self.patterns;
+        let caps = patterns.item.captures(&self.lines[start]).unwrap();
+        let mut value = caps[2].to_string();
+        for line in &self.lines[start + 1..=end] {
+            value.push('\n');
+            value.push_str(line.trim_start().trim_end_matches('\r'));
+        }
+        value
+    }
+
+    fn section_start(&self, section: &str) -> Option<usize> {
+        let patterns = &self.patterns;
+        self.lines.iter().position(|line| {
+            patterns
+                .section
+                .captures(line)
+                .map_or(false, |caps| &caps[1] == section)
+        })
+    }
+
+    fn section_end(&self, section: &str) -> usize {
+        let patterns = &self.patterns;
+        match self.section_start(section) {
+            Some(start) => {
+                let mut end = start + 1;
+                while end < self.lines.len() && !patterns.section.is_match(&self.lines[end]) {
+                    end += 1;
+                }
+                end
+            }
+            None => self.lines.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Document;
+
+    #[test]
+    fn get_set_unset_round_trip() {
+        let mut doc = Document::parse("");
+        assert_eq!(doc.get("section", "key"), None);
+
+        doc.set("section", "key", "value");
+        assert_eq!(doc.get("section", "key"), Some("value".to_string()));
+
+        doc.unset("section", "key");
+        assert_eq!(doc.get("section", "key"), None);
+    }
+
+    #[test]
+    fn get_joins_continuation_lines() {
+        let doc = Document::parse("[section]\nkey = first\n second\n\tthird\n");
+        assert_eq!(
+            doc.get("section", "key"),
+            Some("first\nsecond\nthird".to_string())
+        );
+    }
+
+    #[test]
+    fn set_appends_to_existing_section() {
+        let mut doc = Document::parse("[section]\nother = 1\n");
+        doc.set("section", "key", "value");
+        assert_eq!(
+            doc.render(),
+            "[section]\nother = 1\nkey = value\n"
+        );
+    }
+
+    #[test]
+    fn set_creates_new_section() {
+        let mut doc = Document::parse("[other]\nkey = 1\n");
+        doc.set("section", "key", "value");
+        assert_eq!(
+            doc.render(),
+            "[other]\nkey = 1\n\n[section]\nkey = value\n"
+        );
+    }
+
+    #[test]
+    fn unset_absent_key_is_a_no_op() {
+        let mut doc = Document::parse("[section]\nother = 1\n");
+        doc.unset("section", "key");
+        assert_eq!(doc.render(), "[section]\nother = 1\n");
+    }
+
+    #[test]
+    fn set_leaves_unrelated_content_byte_identical() {
+        let original = "; a comment\n[section]\nother = 1\n\n[another]\nkey = 2\n";
+        let mut doc = Document::parse(original);
+        doc.set("section", "key", "value");
+        assert_eq!(
+            doc.render(),
+            "; a comment\n[section]\nother = 1\n\nkey = value\n[another]\nkey = 2\n"
+        );
+    }
+}
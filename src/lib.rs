@@ -19,6 +19,7 @@
 
 extern crate failure;
 extern crate linked_hash_map;
+extern crate regex;
 extern crate sha1;
 #[macro_use]
 extern crate slog;
@@ -27,7 +28,12 @@ extern crate slog_journald;
 extern crate slog_term;
 
 pub mod error;
+pub mod lock;
+pub mod log_file;
 pub mod resource;
+pub mod state_cache;
+pub mod vfs;
+mod ini;
 mod util;
 
 pub mod fs;
@@ -35,30 +41,113 @@ pub mod meta;
 
 pub use meta::Reality;
 
+use std::env;
+use std::io;
+use std::path;
 use std::process;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+/// Options controlling how [`apply_with_options`] sets up logging, the
+/// verify-state cache, and the advisory lock.
+#[derive(Debug, Default)]
+pub struct Options {
+    log_file: Option<LogFileOptions>,
+    state_cache: Option<path::PathBuf>,
+    lock_file: Option<path::PathBuf>,
+}
+
+/// Configures the rotating, on-disk audit log that records every realized
+/// configuration run, even when `journald` is unavailable.
+#[derive(Clone, Debug)]
+pub struct LogFileOptions {
+    /// Path of the primary log file.
+    pub path: path::PathBuf,
+    /// Maximum size in bytes before the log file is rotated. `None` disables
+    /// rotation entirely.
+    pub max_size: Option<u64>,
+    /// Maximum number of rotated historical files to keep.
+    pub max_files: usize,
+}
+
+impl Options {
+    /// Creates a default set of options, logging only to the terminal and
+    /// `journald`.
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    /// Additionally logs to a rotating file, as configured by `log_file`.
+    pub fn with_log_file(mut self, log_file: LogFileOptions) -> Options {
+        self.log_file = Some(log_file);
+        self
+    }
+
+    /// Enables the persisted verify-state cache, backed by a file at `path`,
+    /// letting `verify` skip re-hashing files whose size and modification
+    /// time are unchanged since the last successful `realize`.
+    pub fn with_state_cache<P>(mut self, path: P) -> Options
+    where
+        P: Into<path::PathBuf>,
+    {
+        self.state_cache = Some(path.into());
+        self
+    }
+
+    /// Acquires a non-blocking advisory lock at `path` around `verify` and
+    /// `realize`, instead of the default location derived from the reality
+    /// being applied. See `lock::try_with_lock_no_wait`.
+    pub fn with_lock_file<P>(mut self, path: P) -> Options
+    where
+        P: Into<path::PathBuf>,
+    {
+        self.lock_file = Some(path.into());
+        self
+    }
+}
+
 /// Runs the supplied configuration function and applies the resulting reality.
 /// This should be called from your `main` method, it assumes that it is safe to
 /// call `std::process::exit` for example.
 pub fn apply<F>(config: F)
+where
+    F: FnOnce(&mut Reality),
+{
+    apply_with_options(Options::new(), config)
+}
+
+/// Like [`apply`], but allows customizing logging, the verify-state cache and
+/// the lock file location via `options`.
+pub fn apply_with_options<F>(options: Options, config: F)
 where
     F: FnOnce(&mut Reality),
 {
     use slog::Drain;
-    let decorator = slog_term::TermDecorator::new().stderr().build();
-    let term_drain = slog_term::CompactFormat::new(decorator)
-        .use_utc_timestamp()
-        .build()
-        .fuse();
-    let term_drain = slog_async::Async::new(term_drain).build().fuse();
-    let journald_drain = slog_journald::JournaldDrain.fuse();
 
-    let drain = slog::Duplicate::new(term_drain, journald_drain).fuse();
-    let log = slog::Logger::root(drain.fuse(), o!("realize-version" => VERSION));
+    let drain = base_drain();
 
-    match apply_inner(&log, config) {
+    let log = match options.log_file {
+        Some(ref log_file_options) => {
+            let file = log_file::LogFile::new(
+                log_file_options.path.clone(),
+                log_file_options.max_size,
+                log_file_options.max_files,
+            );
+            let file_drain = log_file::LogFileDrain::new(file).fuse();
+            let drain = slog::Duplicate::new(drain, file_drain).fuse();
+            slog::Logger::root(drain, o!("realize-version" => VERSION))
+        }
+        None => slog::Logger::root(drain, o!("realize-version" => VERSION)),
+    };
+
+    let result = apply_inner(
+        &log,
+        &vfs::RealVfs,
+        options.state_cache.as_deref(),
+        Locking::Enabled(options.lock_file),
+        config,
+    );
+    match result {
         Ok(()) => (),
         Err(e) => {
             error!(log, "{}", e);
@@ -72,26 +161,113 @@ where
     }
 }
 
-fn apply_inner<F>(log: &slog::Logger, config: F) -> error::Result<()>
+/// Runs the supplied configuration function and logs what `apply` would do,
+/// without touching the file system. `verify` still runs for real, but any
+/// pending `realize` actions are only logged (e.g. "would create file ..."),
+/// via a `vfs::DryRunVfs`.
+pub fn apply_dry_run<F>(config: F)
+where
+    F: FnOnce(&mut Reality),
+{
+    let log = slog::Logger::root(base_drain(), o!("realize-version" => VERSION));
+
+    let dry_run_vfs = vfs::DryRunVfs::new(&log);
+    match apply_inner(&log, &dry_run_vfs, None, Locking::Disabled, config) {
+        Ok(()) => (),
+        Err(e) => {
+            error!(log, "{}", e);
+            for error in e.iter_causes() {
+                error!(log, " → {}", error);
+            }
+            drop(log);
+            process::exit(1);
+        }
+    }
+}
+
+/// Builds the terminal + `journald` drain shared by [`apply_with_options`]
+/// and [`apply_dry_run`], fused so it never fails to log.
+fn base_drain() -> impl slog::Drain<Ok = (), Err = slog::Never> {
+    use slog::Drain;
+
+    let decorator = slog_term::TermDecorator::new().stderr().build();
+    let term_drain = slog_term::CompactFormat::new(decorator)
+        .use_utc_timestamp()
+        .build()
+        .fuse();
+    let term_drain = slog_async::Async::new(term_drain).build().fuse();
+    let journald_drain = slog_journald::JournaldDrain.fuse();
+
+    slog::Duplicate::new(term_drain, journald_drain).fuse()
+}
+
+/// Whether `apply_inner` should acquire a lock around `verify`/`realize`, and
+/// if so, at which path. Modeled as an enum rather than a separate
+/// `bool`/`Option` pair, since `Some(path)` with locking disabled would
+/// otherwise silently drop the path.
+enum Locking {
+    /// Don't acquire a lock at all, e.g. for `apply_dry_run`.
+    Disabled,
+    /// Acquire a lock, at the given path if one was configured, or otherwise
+    /// at `default_lock_path`.
+    Enabled(Option<path::PathBuf>),
+}
+
+fn apply_inner<F>(
+    log: &slog::Logger,
+    vfs: &vfs::Vfs,
+    state_cache_path: Option<&path::Path>,
+    locking: Locking,
+    config: F,
+) -> error::Result<()>
 where
     F: FnOnce(&mut Reality),
 {
     use resource::Resource;
 
-    let context = resource::Context { log: log };
+    let state_cache = match state_cache_path {
+        Some(path) => Some(state_cache::StateCache::open(path)?),
+        None => None,
+    };
+
+    let context = resource::Context {
+        log: log,
+        vfs: vfs,
+        state_cache: state_cache.as_ref(),
+    };
 
     let mut resource = Reality::new(log.clone());
 
     config(&mut resource);
 
-    info!(log, "Applying configuration");
-    if resource.verify(&context)? {
-        info!(log, "Everything up to date, nothing to do");
-    } else {
-        resource.realize(&context)?;
-        info!(log, "Configuration applied");
+    let run = || -> error::Result<()> {
+        info!(log, "Applying configuration");
+        if resource.verify(&context)? {
+            info!(log, "Everything up to date, nothing to do");
+        } else {
+            resource.realize(&context)?;
+            info!(log, "Configuration applied");
+        }
+        Ok(())
+    };
+
+    match locking {
+        Locking::Enabled(lock_path) => {
+            let lock_path =
+                lock_path.unwrap_or_else(|| default_lock_path(&resource));
+            lock::try_with_lock_no_wait(&lock_path, run)
+        }
+        Locking::Disabled => run(),
     }
-    Ok(())
+}
+
+fn default_lock_path(reality: &Reality) -> path::PathBuf {
+    use resource::Resource;
+
+    let digest = util::sha1(io::Cursor::new(
+        format!("{}", reality.key()).into_bytes(),
+    )).expect("hashing from memory never fails");
+    env::temp_dir().join(format!("realize-{}.lock", digest))
 }
 
 #[cfg(test)]
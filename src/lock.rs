@@ -0,0 +1,117 @@
+//! A non-blocking, advisory file lock, used to prevent concurrent `realize`
+//! runs against the same reality from racing and corrupting files.
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::process;
+
+use error;
+
+/// Acquires a non-blocking advisory lock at `path`, runs `body`, and removes
+/// the lock file afterwards — even if `body` returns an error or panics.
+/// Fails immediately with `error::LockBusy` if the lock is already held,
+/// rather than blocking until it becomes available.
+///
+/// The lock is implemented by exclusively creating `path` (`O_CREAT |
+/// O_EXCL`) rather than `flock(2)`, since this crate denies `unsafe_code`.
+pub fn try_with_lock_no_wait<F, T>(path: &path::Path, body: F) -> error::Result<T>
+where
+    F: FnOnce() -> error::Result<T>,
+{
+    let _guard = Guard::acquire(path)?;
+    body()
+}
+
+struct Guard<'a> {
+    path: &'a path::Path,
+}
+
+impl<'a> Guard<'a> {
+    fn acquire(path: &'a path::Path) -> error::Result<Guard<'a>> {
+        use failure::ResultExt;
+
+        match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(mut file) => {
+                writeln!(file, "pid={}", process::id())
+                    .with_context(|_| format!("Failed to write lock file {:?}", path))?;
+                Ok(Guard { path: path })
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let holder = fs::read_to_string(path).unwrap_or_default();
+                Err(error::LockBusy {
+                    lock_path: path.to_path_buf(),
+                    holder: holder.trim().to_string(),
+                }.into())
+            }
+            Err(e) => Err(e).with_context(|_| format!("Failed to create lock file {:?}", path))?,
+        }
+    }
+}
+
+impl<'a> Drop for Guard<'a> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io;
+    use std::path;
+    use std::process;
+    use std::sync::atomic;
+
+    use error;
+
+    use super::try_with_lock_no_wait;
+
+    fn unique_path(name: &str) -> path::PathBuf {
+        static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
+        env::temp_dir().join(format!("realize-lock-test-{}-{}-{}", process::id(), n, name))
+    }
+
+    #[test]
+    fn second_acquire_fails_while_held() {
+        let path = unique_path("busy");
+
+        try_with_lock_no_wait(&path, || {
+            let inner: error::Result<()> = try_with_lock_no_wait(&path, || Ok(()));
+            match inner {
+                Err(e) => {
+                    let lock_busy = e.downcast::<error::LockBusy>()
+                        .expect("expected a LockBusy error");
+                    assert_eq!(lock_busy.lock_path, path);
+                    assert!(lock_busy.holder.starts_with("pid="));
+                }
+                Ok(()) => panic!("expected the second acquire to fail"),
+            }
+            Ok(())
+        }).unwrap();
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn guard_removes_lock_file_after_success() {
+        let path = unique_path("cleanup-success");
+
+        try_with_lock_no_wait(&path, || Ok(())).unwrap();
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn guard_removes_lock_file_after_error() {
+        let path = unique_path("cleanup-error");
+
+        let result: error::Result<()> = try_with_lock_no_wait(&path, || {
+            Err(io::Error::new(io::ErrorKind::Other, "boom").into())
+        });
+
+        assert!(result.is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,251 @@
+//! A rotating log file, exposed as a `slog` drain so that it can be wired
+//! up alongside the terminal and journald drains in `apply`.
+use std::ffi;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+
+use slog;
+
+/// Appends bytes to a log file, rotating it by size.
+///
+/// Before each append, if the target file already exceeds `max_size` bytes,
+/// the file is rotated: `name.log.{n}` is renamed to `name.log.{n+1}` for
+/// `n` from `max_files - 1` down to `1`, then `name.log` becomes
+/// `name.log.1`, and anything beyond `max_files` is dropped. If `max_size`
+/// is `None`, the file is never rotated.
+#[derive(Debug)]
+pub struct LogFile {
+    path: path::PathBuf,
+    max_size: Option<u64>,
+    max_files: usize,
+}
+
+impl LogFile {
+    /// Creates a log file at `path` that rotates once it exceeds `max_size`
+    /// bytes, keeping at most `max_files` historical copies.
+    pub fn new<P>(path: P, max_size: Option<u64>, max_files: usize) -> LogFile
+    where
+        P: Into<path::PathBuf>,
+    {
+        LogFile {
+            path: path.into(),
+            max_size: max_size,
+            max_files: max_files,
+        }
+    }
+
+    /// Appends `bytes` as-is, rotating the file first if it has grown past
+    /// `max_size`. The caller is responsible for including any trailing
+    /// newline.
+    pub fn append(&self, bytes: &[u8]) -> io::Result<()> {
+        if self.should_rotate()? {
+            self.rotate()?;
+        }
+
+        let mut f = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        f.write_all(bytes)
+    }
+
+    fn should_rotate(&self) -> io::Result<bool> {
+        let max_size = match self.max_size {
+            Some(max_size) => max_size,
+            None => return Ok(false),
+        };
+
+        match fs::metadata(&self.path) {
+            Ok(metadata) => Ok(metadata.len() > max_size),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return match fs::remove_file(&self.path) {
+                Ok(()) => Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e),
+            };
+        }
+
+        let oldest = self.rotated_path(self.max_files);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(n + 1))?;
+            }
+        }
+
+        if self.path.exists() {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize) -> path::PathBuf {
+        let mut name: ffi::OsString = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        path::PathBuf::from(name)
+    }
+}
+
+/// A `slog` drain that formats records as plain text lines and appends them
+/// to a rotating `LogFile`. Composes with the existing terminal/journald fan
+/// out via `slog::Duplicate`.
+#[derive(Debug)]
+pub struct LogFileDrain {
+    file: LogFile,
+}
+
+impl LogFileDrain {
+    /// Wraps `file` as a `slog::Drain`.
+    pub fn new(file: LogFile) -> LogFileDrain {
+        LogFileDrain { file: file }
+    }
+}
+
+impl slog::Drain for LogFileDrain {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        use slog::KV;
+
+        let mut line = format!("{} {} {}", record.level(), record.module(), record.msg());
+
+        {
+            let mut serializer = LineSerializer(&mut line);
+            values.serialize(record, &mut serializer)?;
+            record.kv().serialize(record, &mut serializer)?;
+        }
+
+        line.push('\n');
+        self.file.append(line.as_bytes())
+    }
+}
+
+struct LineSerializer<'a>(&'a mut String);
+
+impl<'a> slog::Serializer for LineSerializer<'a> {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.push_str(&format!(" {}={}", key, val));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path;
+    use std::process;
+    use std::sync::atomic;
+
+    use super::LogFile;
+
+    fn unique_path(name: &str) -> path::PathBuf {
+        static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
+        env::temp_dir().join(format!("realize-log-file-test-{}-{}-{}", process::id(), n, name))
+    }
+
+    fn read(path: &path::Path) -> String {
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    fn cleanup(path: &path::Path, max_files: usize) {
+        let _ = fs::remove_file(path);
+        for n in 1..=max_files {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{}", n));
+            let _ = fs::remove_file(path::PathBuf::from(name));
+        }
+    }
+
+    #[test]
+    fn rotates_and_chains_historical_files() {
+        let path = unique_path("rotates-and-chains");
+        let log_file = LogFile::new(path.clone(), Some(0), 2);
+
+        log_file.append(b"first").unwrap();
+        log_file.append(b"second").unwrap();
+        log_file.append(b"third").unwrap();
+        log_file.append(b"fourth").unwrap();
+
+        assert_eq!(read(&path), "fourth");
+        let dot1 = {
+            let mut name = path.as_os_str().to_owned();
+            name.push(".1");
+            path::PathBuf::from(name)
+        };
+        let dot2 = {
+            let mut name = path.as_os_str().to_owned();
+            name.push(".2");
+            path::PathBuf::from(name)
+        };
+        let dot3 = {
+            let mut name = path.as_os_str().to_owned();
+            name.push(".3");
+            path::PathBuf::from(name)
+        };
+        assert_eq!(read(&dot1), "third");
+        assert_eq!(read(&dot2), "second");
+        assert!(!dot3.exists(), "entries beyond max_files should be dropped");
+
+        cleanup(&path, 2);
+    }
+
+    #[test]
+    fn max_files_zero_replaces_instead_of_rotating() {
+        let path = unique_path("max-files-zero");
+        let log_file = LogFile::new(path.clone(), Some(0), 0);
+
+        log_file.append(b"first").unwrap();
+        log_file.append(b"second").unwrap();
+
+        assert_eq!(read(&path), "second");
+        let dot1 = {
+            let mut name = path.as_os_str().to_owned();
+            name.push(".1");
+            path::PathBuf::from(name)
+        };
+        assert!(!dot1.exists());
+
+        cleanup(&path, 0);
+    }
+
+    #[test]
+    fn max_size_none_never_rotates() {
+        let path = unique_path("max-size-none");
+        let log_file = LogFile::new(path.clone(), None, 2);
+
+        log_file.append(b"a").unwrap();
+        log_file.append(b"b").unwrap();
+        log_file.append(b"c").unwrap();
+
+        assert_eq!(read(&path), "abc");
+        let dot1 = {
+            let mut name = path.as_os_str().to_owned();
+            name.push(".1");
+            path::PathBuf::from(name)
+        };
+        assert!(!dot1.exists());
+
+        cleanup(&path, 2);
+    }
+}
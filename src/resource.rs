@@ -7,6 +7,8 @@ use std::path;
 use slog;
 
 use error;
+use state_cache;
+use vfs;
 
 /// Something that is managed by `realize` and that can be realized on the
 /// target system.
@@ -70,6 +72,13 @@ pub trait Ensurer {
 pub struct Context<'a> {
     /// A structured logger to use when logging contextual information.
     pub log: &'a slog::Logger,
+    /// The file system to realize and verify resources against. Defaults to
+    /// `vfs::RealVfs`, but may be a `vfs::DryRunVfs` when previewing changes.
+    pub vfs: &'a vfs::Vfs,
+    /// A persisted cache of verify state, shared by all resources in this
+    /// `Context`, that resource types may consult to skip expensive
+    /// re-verification of unchanged state. `None` if caching is disabled.
+    pub state_cache: Option<&'a state_cache::StateCache>,
 }
 
 impl fmt::Display for Key {
@@ -0,0 +1,332 @@
+//! A persisted cache of verified file state, letting `verify` skip
+//! re-hashing files whose size and modification time haven't changed since
+//! the last successful `realize`. Modeled on Mercurial's dirstate.
+use std::cell::RefCell;
+use std::collections;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path;
+use std::time;
+
+use error;
+use resource;
+
+/// The recorded state of a realized resource, captured at the time it was
+/// last successfully realized.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    /// The file path this entry describes.
+    pub path: path::PathBuf,
+    /// The file size in bytes, as of the last successful `realize`.
+    pub size: u64,
+    /// The file modification time, as of the last successful `realize`.
+    pub mtime: time::SystemTime,
+    /// The SHA-1 digest of the file contents, as of the last successful
+    /// `realize`.
+    pub sha1: String,
+}
+
+/// A persisted, per-`Reality` cache of `Entry` values, keyed by
+/// `resource::Key`. Backed by a plain-text state file that is rewritten
+/// atomically (write a temp file, then rename) to avoid corruption on
+/// interrupted runs.
+///
+/// The persisted file only ever stores `resource::Key::to_string()`, since
+/// `Key` itself has no way to be parsed back out of that (lossy) rendering.
+/// To avoid aliasing two structurally different keys that happen to render
+/// to the same string, entries loaded from disk are kept in `loaded`, keyed
+/// by that string, until a real `resource::Key` claims them via `get`; from
+/// then on they live in `entries`, keyed by the real `Key` itself.
+#[derive(Debug)]
+pub struct StateCache {
+    path: path::PathBuf,
+    entries: RefCell<collections::HashMap<resource::Key, Entry>>,
+    loaded: RefCell<collections::HashMap<String, Entry>>,
+}
+
+impl StateCache {
+    /// Opens the state file at `path`, loading any entries it already
+    /// contains. The file need not exist yet; it is created on the first
+    /// `put` or `remove`.
+    pub fn open<P>(path: P) -> error::Result<StateCache>
+    where
+        P: Into<path::PathBuf>,
+    {
+        let path = path.into();
+        let loaded = match fs::File::open(&path) {
+            Ok(file) => parse(file)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => collections::HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(StateCache {
+            path: path,
+            entries: RefCell::new(collections::HashMap::new()),
+            loaded: RefCell::new(loaded),
+        })
+    }
+
+    /// Looks up the cached entry for `key`, if any.
+    pub fn get(&self, key: &resource::Key) -> Option<Entry> {
+        if let Some(entry) = self.entries.borrow().get(key).cloned() {
+            return Some(entry);
+        }
+
+        let claimed = self.loaded.borrow_mut().remove(&key.to_string());
+        if let Some(entry) = claimed {
+            self.entries
+                .borrow_mut()
+                .insert(key.clone(), entry.clone());
+            return Some(entry);
+        }
+
+        None
+    }
+
+    /// Records (or overwrites) the entry for `key`, and persists the cache
+    /// to disk.
+    pub fn put(&self, key: &resource::Key, entry: Entry) -> error::Result<()> {
+        self.loaded.borrow_mut().remove(&key.to_string());
+        self.entries.borrow_mut().insert(key.clone(), entry);
+        self.save()
+    }
+
+    /// Removes any cached entry for `key`, and persists the cache to disk.
+    pub fn remove(&self, key: &resource::Key) -> error::Result<()> {
+        self.loaded.borrow_mut().remove(&key.to_string());
+        self.entries.borrow_mut().remove(key);
+        self.save()
+    }
+
+    fn save(&self) -> error::Result<()> {
+        use failure::ResultExt;
+
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut f = fs::File::create(&tmp_path).with_context(|_| {
+                format!("Failed to create state cache temp file {:?}", tmp_path)
+            })?;
+            for (key, entry) in self.entries.borrow().iter() {
+                let key = key.to_string();
+                write_entry(&mut f, &key, entry)
+                    .with_context(|_| format!("Failed to write state cache entry for {:?}", key))?;
+            }
+            for (key, entry) in self.loaded.borrow().iter() {
+                write_entry(&mut f, key, entry)
+                    .with_context(|_| format!("Failed to write state cache entry for {:?}", key))?;
+            }
+        }
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|_| format!("Failed to replace state cache {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+fn write_entry<W>(w: &mut W, key: &str, entry: &Entry) -> io::Result<()>
+where
+    W: Write,
+{
+    let duration = entry
+        .mtime
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_else(|_| time::Duration::new(0, 0));
+    writeln!(
+        w,
+        "{}\t{}\t{}\t{}.{:09}\t{}",
+        escape(key),
+        escape(&entry.path.to_string_lossy()),
+        entry.size,
+        duration.as_secs(),
+        duration.subsec_nanos(),
+        entry.sha1
+    )
+}
+
+fn parse<R>(mut read: R) -> error::Result<collections::HashMap<String, Entry>>
+where
+    R: Read,
+{
+    use failure::ResultExt;
+
+    let mut contents = String::new();
+    read.read_to_string(&mut contents)
+        .with_context(|_| "Failed to read state cache")?;
+
+    let mut entries = collections::HashMap::new();
+    for line in contents.lines() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 5 {
+            return Err(malformed(line));
+        }
+
+        let key = unescape(fields[0]);
+        let path = path::PathBuf::from(unescape(fields[1]));
+        let size = fields[2].parse().map_err(|_| malformed(line))?;
+
+        let mut mtime_parts = fields[3].splitn(2, '.');
+        let secs = mtime_parts
+            .next()
+            .ok_or_else(|| malformed(line))
+            .and_then(|s| s.parse().map_err(|_| malformed(line)))?;
+        let nanos = mtime_parts
+            .next()
+            .unwrap_or("0")
+            .parse()
+            .map_err(|_| malformed(line))?;
+        let mtime = time::UNIX_EPOCH + time::Duration::new(secs, nanos);
+
+        let sha1 = fields[4].to_string();
+
+        entries.insert(
+            key,
+            Entry {
+                path: path,
+                size: size,
+                mtime: mtime,
+                sha1: sha1,
+            },
+        );
+    }
+
+    Ok(entries)
+}
+
+fn malformed(line: &str) -> error::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Malformed state cache line: {:?}", line),
+    ).into()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some(other) => result.push(other),
+                None => {}
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path;
+    use std::process;
+    use std::sync::atomic;
+    use std::time;
+
+    use resource;
+
+    use super::{Entry, StateCache};
+
+    fn unique_path(name: &str) -> path::PathBuf {
+        static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
+        env::temp_dir().join(format!(
+            "realize-state-cache-test-{}-{}-{}",
+            process::id(),
+            n,
+            name
+        ))
+    }
+
+    fn entry() -> Entry {
+        Entry {
+            path: path::PathBuf::from("/some/file"),
+            size: 42,
+            mtime: time::UNIX_EPOCH + time::Duration::new(1234, 5678),
+            sha1: "deadbeef".to_string(),
+        }
+    }
+
+    #[test]
+    fn put_persists_across_reopen() {
+        let path = unique_path("put-persists");
+        let key = resource::Key::String("a".to_string());
+
+        let cache = StateCache::open(&path).unwrap();
+        cache.put(&key, entry()).unwrap();
+
+        let reopened = StateCache::open(&path).unwrap();
+        assert_eq!(reopened.get(&key), Some(entry()));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn remove_persists_across_reopen() {
+        let path = unique_path("remove-persists");
+        let key = resource::Key::String("a".to_string());
+
+        let cache = StateCache::open(&path).unwrap();
+        cache.put(&key, entry()).unwrap();
+        cache.remove(&key).unwrap();
+
+        let reopened = StateCache::open(&path).unwrap();
+        assert_eq!(reopened.get(&key), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_does_not_leave_a_temp_file_behind() {
+        let path = unique_path("no-temp-leftover");
+        let key = resource::Key::String("a".to_string());
+
+        let cache = StateCache::open(&path).unwrap();
+        cache.put(&key, entry()).unwrap();
+
+        assert!(path.exists());
+        assert!(!path.with_extension("tmp").exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_rejects_malformed_cache_file() {
+        let path = unique_path("malformed");
+        fs::write(&path, "not\tenough\tfields\n").unwrap();
+
+        assert!(StateCache::open(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn distinct_keys_that_render_the_same_do_not_alias_in_memory() {
+        let path = unique_path("no-alias");
+        let a = resource::Key::String("a".to_string());
+        let b = resource::Key::Path(path::PathBuf::from("a"));
+        assert_eq!(a.to_string(), b.to_string());
+
+        let cache = StateCache::open(&path).unwrap();
+        cache.put(&a, entry()).unwrap();
+
+        assert_eq!(cache.get(&a), Some(entry()));
+        assert_eq!(cache.get(&b), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
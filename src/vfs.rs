@@ -0,0 +1,329 @@
+//! A virtual file system abstraction, letting resources be verified and
+//! realized against something other than the real `std::fs` — in
+//! particular, a dry-run backend that only logs intended changes.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os;
+use std::path;
+
+use slog;
+
+/// Abstracts over the file system operations used by resources, so that they
+/// can be pointed at the real file system or at a dry-run (or, in principle,
+/// in-memory) backend instead.
+pub trait Vfs: fmt::Debug {
+    /// Returns metadata about `path`, as `std::fs::metadata` does. Follows
+    /// symlinks, so this never reports `is_symlink()`.
+    fn metadata(&self, path: &path::Path) -> io::Result<fs::Metadata>;
+
+    /// Returns metadata about `path` without following a final symlink, as
+    /// `std::fs::symlink_metadata` does.
+    fn symlink_metadata(&self, path: &path::Path) -> io::Result<fs::Metadata>;
+
+    /// Reads the target of the symlink at `path`, as `std::fs::read_link`
+    /// does.
+    fn read_link(&self, path: &path::Path) -> io::Result<path::PathBuf>;
+
+    /// Opens the file at `path` for reading, as `std::fs::File::open` does.
+    fn open(&self, path: &path::Path) -> io::Result<Box<io::Read>>;
+
+    /// Creates (or truncates) the file at `path` and writes `contents` to it.
+    fn create(&self, path: &path::Path, contents: &[u8]) -> io::Result<()>;
+
+    /// Removes the regular file at `path`, as `std::fs::remove_file` does.
+    fn remove_file(&self, path: &path::Path) -> io::Result<()>;
+
+    /// Removes the empty directory at `path`, as `std::fs::remove_dir` does.
+    fn remove_dir(&self, path: &path::Path) -> io::Result<()>;
+
+    /// Creates the directory at `path`, along with any missing parents, as
+    /// `std::fs::create_dir_all` does.
+    fn create_dir_all(&self, path: &path::Path) -> io::Result<()>;
+
+    /// Creates a symlink at `path` pointing to `target`.
+    fn symlink(&self, target: &path::Path, path: &path::Path) -> io::Result<()>;
+}
+
+/// The default `Vfs`, delegating every operation directly to `std::fs`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealVfs;
+
+impl Vfs for RealVfs {
+    fn metadata(&self, path: &path::Path) -> io::Result<fs::Metadata> {
+        fs::metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &path::Path) -> io::Result<fs::Metadata> {
+        fs::symlink_metadata(path)
+    }
+
+    fn read_link(&self, path: &path::Path) -> io::Result<path::PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn open(&self, path: &path::Path) -> io::Result<Box<io::Read>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+
+    fn create(&self, path: &path::Path, contents: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+
+        let mut f = fs::File::create(path)?;
+        f.write_all(contents)
+    }
+
+    fn remove_file(&self, path: &path::Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &path::Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &path::Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn symlink(&self, target: &path::Path, path: &path::Path) -> io::Result<()> {
+        // TODO: add support for other OSes
+        os::unix::fs::symlink(target, path)
+    }
+}
+
+/// A `Vfs` that performs no mutations, logging every intended change instead
+/// (e.g. "would create file ..."). Reads still delegate to the real file
+/// system, so that `verify` reflects actual state.
+#[derive(Clone, Copy, Debug)]
+pub struct DryRunVfs<'a> {
+    log: &'a slog::Logger,
+}
+
+impl<'a> DryRunVfs<'a> {
+    /// Creates a dry-run `Vfs` that logs intended changes to `log`.
+    pub fn new(log: &'a slog::Logger) -> DryRunVfs<'a> {
+        DryRunVfs { log: log }
+    }
+}
+
+impl<'a> Vfs for DryRunVfs<'a> {
+    fn metadata(&self, path: &path::Path) -> io::Result<fs::Metadata> {
+        fs::metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &path::Path) -> io::Result<fs::Metadata> {
+        fs::symlink_metadata(path)
+    }
+
+    fn read_link(&self, path: &path::Path) -> io::Result<path::PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn open(&self, path: &path::Path) -> io::Result<Box<io::Read>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+
+    fn create(&self, path: &path::Path, _contents: &[u8]) -> io::Result<()> {
+        info!(self.log, "Would create file"; "path" => path.to_string_lossy().into_owned());
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &path::Path) -> io::Result<()> {
+        info!(self.log, "Would delete file"; "path" => path.to_string_lossy().into_owned());
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &path::Path) -> io::Result<()> {
+        info!(self.log, "Would delete directory"; "path" => path.to_string_lossy().into_owned());
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &path::Path) -> io::Result<()> {
+        info!(self.log, "Would create directory"; "path" => path.to_string_lossy().into_owned());
+        Ok(())
+    }
+
+    fn symlink(&self, target: &path::Path, path: &path::Path) -> io::Result<()> {
+        info!(self.log, "Would create symlink";
+              "path" => path.to_string_lossy().into_owned(),
+              "target" => target.to_string_lossy().into_owned());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fmt;
+    use std::fs;
+    use std::io;
+    use std::path;
+    use std::process;
+    use std::sync::atomic;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use slog;
+    use slog::Drain;
+
+    use super::{DryRunVfs, Vfs};
+
+    /// A `Drain` that records each logged line (message plus key/value
+    /// pairs) into a shared buffer instead of emitting it anywhere, so that
+    /// tests can assert on what `DryRunVfs` would have logged.
+    #[derive(Debug)]
+    struct RecordingDrain {
+        lines: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RecordingDrain {
+        fn new() -> (RecordingDrain, Arc<Mutex<Vec<String>>>) {
+            let lines = Arc::new(Mutex::new(Vec::new()));
+            (
+                RecordingDrain {
+                    lines: lines.clone(),
+                },
+                lines,
+            )
+        }
+    }
+
+    impl Drain for RecordingDrain {
+        type Ok = ();
+        type Err = io::Error;
+
+        fn log(
+            &self,
+            record: &slog::Record,
+            values: &slog::OwnedKVList,
+        ) -> Result<Self::Ok, Self::Err> {
+            use slog::KV;
+
+            let mut line = format!("{}", record.msg());
+            {
+                let mut serializer = LineSerializer(&mut line);
+                values.serialize(record, &mut serializer)?;
+                record.kv().serialize(record, &mut serializer)?;
+            }
+            self.lines.lock().unwrap().push(line);
+            Ok(())
+        }
+    }
+
+    struct LineSerializer<'a>(&'a mut String);
+
+    impl<'a> slog::Serializer for LineSerializer<'a> {
+        fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+            self.0.push_str(&format!(" {}={}", key, val));
+            Ok(())
+        }
+    }
+
+    fn unique_path(name: &str) -> path::PathBuf {
+        static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
+        env::temp_dir().join(format!("realize-vfs-test-{}-{}-{}", process::id(), n, name))
+    }
+
+    #[test]
+    fn create_performs_no_io() {
+        let path = unique_path("create");
+        let (drain, _lines) = RecordingDrain::new();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        let vfs = DryRunVfs::new(&log);
+
+        vfs.create(&path, b"hello").unwrap();
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn remove_file_performs_no_io() {
+        let path = unique_path("remove-file");
+        fs::write(&path, b"hello").unwrap();
+        let (drain, _lines) = RecordingDrain::new();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        let vfs = DryRunVfs::new(&log);
+
+        vfs.remove_file(&path).unwrap();
+
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn remove_dir_performs_no_io() {
+        let path = unique_path("remove-dir");
+        fs::create_dir_all(&path).unwrap();
+        let (drain, _lines) = RecordingDrain::new();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        let vfs = DryRunVfs::new(&log);
+
+        vfs.remove_dir(&path).unwrap();
+
+        assert!(path.exists());
+        fs::remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn create_dir_all_performs_no_io() {
+        let path = unique_path("create-dir-all");
+        let (drain, _lines) = RecordingDrain::new();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        let vfs = DryRunVfs::new(&log);
+
+        vfs.create_dir_all(&path).unwrap();
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn symlink_performs_no_io() {
+        let path = unique_path("symlink");
+        let target = unique_path("symlink-target");
+        let (drain, _lines) = RecordingDrain::new();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        let vfs = DryRunVfs::new(&log);
+
+        vfs.symlink(&target, &path).unwrap();
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn mutations_log_the_would_be_action_and_path() {
+        let path = unique_path("log-message");
+        let (drain, lines) = RecordingDrain::new();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        let vfs = DryRunVfs::new(&log);
+
+        vfs.create(&path, b"hello").unwrap();
+        vfs.remove_file(&path).unwrap();
+        vfs.remove_dir(&path).unwrap();
+        vfs.create_dir_all(&path).unwrap();
+        vfs.symlink(path::Path::new("/target"), &path).unwrap();
+
+        let lines = lines.lock().unwrap();
+        let path = path.to_string_lossy().into_owned();
+        assert!(lines[0].starts_with("Would create file"));
+        assert!(lines[0].contains(&format!("path={}", path)));
+        assert!(lines[1].starts_with("Would delete file"));
+        assert!(lines[2].starts_with("Would delete directory"));
+        assert!(lines[3].starts_with("Would create directory"));
+        assert!(lines[4].starts_with("Would create symlink"));
+        assert!(lines[4].contains("target=/target"));
+    }
+
+    #[test]
+    fn reads_delegate_to_the_real_file_system() {
+        let path = unique_path("reads-delegate");
+        fs::write(&path, b"hello").unwrap();
+        let (drain, _lines) = RecordingDrain::new();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        let vfs = DryRunVfs::new(&log);
+
+        assert_eq!(vfs.metadata(&path).unwrap().len(), 5);
+
+        fs::remove_file(&path).unwrap();
+    }
+}